@@ -11,6 +11,8 @@ use offscreen_gl_context::{GLContext, NativeGLContextMethods, GLContextDispatche
 use offscreen_gl_context::{OSMesaContext, OSMesaContextHandle};
 use offscreen_gl_context::{ColorAttachmentType, GLContextAttributes, GLLimits};
 use profiler::BackendProfileCounters;
+#[cfg(feature = "capture")]
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::f32;
 use std::hash::BuildHasherDefault;
@@ -32,8 +34,23 @@ use webrender_traits::{ExternalImageId, ScrollLayerId, WebGLCommand};
 // map from cache texture ID to native texture.
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "capture", derive(Deserialize, Serialize))]
 pub struct CacheTextureId(pub usize);
 
+// A monotonically increasing frame counter used by the GPU cache to
+// know when a block was last touched, so that blocks belonging to
+// content that is no longer requested can be evicted and their texels
+// reused by a later allocation.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub struct FrameId(pub usize);
+
+// Scopes a frame, its scroll layers and its texture-update batches to one
+// of the several independent scenes a single renderer instance can host
+// (e.g. a main content view plus an overlay UI). Each document is updated
+// at its own rate and composited into its own output rect in z-order.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct DocumentId(pub usize);
+
 // Represents the source for a texture.
 // These are passed from throughout the
 // pipeline until they reach the rendering
@@ -41,6 +58,7 @@ pub struct CacheTextureId(pub usize);
 // native texture ID.
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "capture", derive(Deserialize, Serialize))]
 pub enum SourceTexture {
     Invalid,
     TextureCache(CacheTextureId),
@@ -48,6 +66,38 @@ pub enum SourceTexture {
     External(ExternalImageId),
 }
 
+// The backing for an external image, returned by the embedder's
+// `ExternalImageHandler` when the rendering thread resolves a
+// `SourceTexture::External`. The source is either a texture the host has
+// already uploaded to the shared GL context, or a CPU buffer that the
+// renderer uploads into the texture cache itself.
+pub enum ExternalImageSource<'a> {
+    NativeTexture(u32),         // Is actually a gl::GLuint
+    RawData(&'a [u8]),
+}
+
+// A locked external image: its current backing plus the sub-rectangle of
+// that backing, in normalized texture coordinates, that should be
+// sampled. The producer may double-buffer behind the handle, so the UV
+// rect can change from one `lock` to the next.
+pub struct ExternalImage<'a> {
+    pub u0: f32,
+    pub v0: f32,
+    pub u1: f32,
+    pub v1: f32,
+    pub source: ExternalImageSource<'a>,
+}
+
+/// Registered by the embedder to resolve `SourceTexture::External` at draw
+/// time, decoupling externally-produced surfaces (video frames, camera
+/// feeds, compositor surfaces, and WebGL contexts) from the offscreen-GL
+/// machinery. `lock`/`unlock` bracket each use of an image so the producer
+/// can swap buffers safely.
+pub trait ExternalImageHandler {
+    fn lock(&mut self, id: ExternalImageId) -> ExternalImage;
+    fn unlock(&mut self, id: ExternalImageId);
+}
+
 pub enum GLContextHandleWrapper {
     Native(NativeGLContextHandle),
     OSMesa(OSMesaContextHandle),
@@ -182,14 +232,10 @@ pub enum TextureSampler {
     Color2,
     Mask,
     Cache,
-    Data16,
-    Data32,
-    Data64,
-    Data128,
+    GpuCache,
     Layers,
     RenderTasks,
     Geometry,
-    ResourceRects,
 }
 
 impl TextureSampler {
@@ -243,6 +289,7 @@ pub enum VertexAttribute {
 }
 
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "capture", derive(Deserialize, Serialize))]
 #[repr(C)]
 pub struct PackedColor {
     pub r: u8,
@@ -263,6 +310,7 @@ impl PackedColor {
 }
 
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "capture", derive(Deserialize, Serialize))]
 #[repr(C)]
 pub struct PackedVertexForQuad {
     pub x: f32,
@@ -339,23 +387,47 @@ impl DebugColorVertex {
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "capture", derive(Deserialize, Serialize))]
 pub enum RenderTargetMode {
     None,
     SimpleRenderTarget,
     LayerRenderTarget(i32),      // Number of texture layers
 }
 
+#[cfg_attr(feature = "capture", derive(Deserialize, Serialize))]
 pub enum TextureUpdateOp {
-    Create(u32, u32, ImageFormat, TextureFilter, RenderTargetMode, Option<Arc<Vec<u8>>>),
-    Update(u32, u32, u32, u32, Arc<Vec<u8>>, Option<u32>),
-    Grow(u32, u32, ImageFormat, TextureFilter, RenderTargetMode),
+    Create(u32,
+           u32,
+           #[cfg_attr(feature = "capture", serde(with = "capture::ImageFormatDef"))]
+           ImageFormat,
+           #[cfg_attr(feature = "capture", serde(with = "capture::TextureFilterDef"))]
+           TextureFilter,
+           RenderTargetMode,
+           #[cfg_attr(feature = "capture", serde(with = "capture::opt_blob"))]
+           Option<Arc<Vec<u8>>>),
+    Update(u32,
+           u32,
+           u32,
+           u32,
+           #[cfg_attr(feature = "capture", serde(with = "capture::blob"))]
+           Arc<Vec<u8>>,
+           Option<u32>),
+    Grow(u32,
+         u32,
+         #[cfg_attr(feature = "capture", serde(with = "capture::ImageFormatDef"))]
+         ImageFormat,
+         #[cfg_attr(feature = "capture", serde(with = "capture::TextureFilterDef"))]
+         TextureFilter,
+         RenderTargetMode),
 }
 
+#[cfg_attr(feature = "capture", derive(Deserialize, Serialize))]
 pub struct TextureUpdate {
     pub id: CacheTextureId,
     pub op: TextureUpdateOp,
 }
 
+#[cfg_attr(feature = "capture", derive(Deserialize, Serialize))]
 pub struct TextureUpdateList {
     pub updates: Vec<TextureUpdate>,
 }
@@ -373,39 +445,432 @@ impl TextureUpdateList {
     }
 }
 
+// The width in texels of the single RGBA32F texture that backs the GPU
+// cache. A block never straddles a row boundary, so this is also the
+// largest block that can ever be allocated.
+pub const GPU_CACHE_TEXTURE_WIDTH: u32 = 1024;
+
+// One RGBA32F texel worth of data. Callers fill a contiguous run of these
+// for each block they request; shaders read them back with
+// fetch_from_gpu_cache_1/2/3(address).
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct GpuBlockData {
+    pub data: [f32; 4],
+}
+
+impl GpuBlockData {
+    pub fn empty() -> GpuBlockData {
+        GpuBlockData {
+            data: [0.0; 4],
+        }
+    }
+}
+
+// The absolute location of a block within the cache texture, as integer
+// texel coordinates: `u` is the column of its first texel, `v` its row.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct GpuCacheAddress {
+    pub u: u16,
+    pub v: u16,
+}
+
+// The outcome of a `GpuCache::request`. `needs_write` is true when the
+// block was freshly allocated (either the handle was new, or its previous
+// block was evicted/resized and re-allocated), in which case the caller
+// MUST repopulate every texel via `set_block`. When it is false the
+// block's previous contents are still live at `address` and a primitive
+// whose data is unchanged may skip the upload entirely.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct GpuCacheRequest {
+    pub address: GpuCacheAddress,
+    pub needs_write: bool,
+}
+
+// Index into GpuCache::blocks. Also doubles as the node in the per-length
+// free lists, which keeps reuse O(1).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct BlockIndex(usize);
+
+// A handle returned to a caller when it first requests space in the cache.
+// It is cheap to copy and store on the primitive; the actual texture
+// location is looked up (and possibly re-allocated) through the cache.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct GpuCacheHandle {
+    location: Option<CacheLocation>,
+}
+
+impl GpuCacheHandle {
+    pub fn new() -> GpuCacheHandle {
+        GpuCacheHandle {
+            location: None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct CacheLocation {
+    block_index: BlockIndex,
+    // The allocation generation of the block when this handle was given it.
+    // It only changes when a block is freed and re-handed-out, so a mismatch
+    // means the handle's texels were reclaimed for some other allocation.
+    generation: u32,
+}
+
+// Bookkeeping for a single allocated (or free) run of texels.
+#[derive(Copy, Clone, Debug)]
+struct Block {
+    address: GpuCacheAddress,
+    // The number of texels reserved for this block.
+    len: u16,
+    // Link to the next free block of the same length, or None when this
+    // block is allocated or is the tail of a free list.
+    next_free: Option<BlockIndex>,
+    // The last frame in which this block was requested. The per-frame
+    // sweep evicts allocated blocks that were not touched, so the texels
+    // can be reused by a later allocation.
+    epoch: FrameId,
+    // Bumped every time the block is freed and re-handed-out. Handles carry
+    // the generation they were given so they can detect that their texels
+    // were reclaimed, independently of the per-frame `epoch`.
+    generation: u32,
+    // Whether the block is currently handed out. Freed blocks stay in the
+    // pool (reachable from `free_lists`) but are flagged not-allocated so a
+    // stale handle can tell its texels were reclaimed.
+    allocated: bool,
+    // The last frame a texel of this block was written, so repeated
+    // `set_block` calls only record the block dirty once per frame.
+    last_dirty_frame: FrameId,
+}
+
+// A single row of the cache texture. Every block carved out of a row has
+// the same length, so the row can be treated as a simple bump allocator
+// whose freed slots are threaded onto GpuCache::free_lists.
+struct Row {
+    block_len: u16,
+    // The next free texel column within this row, or None once the row is
+    // exhausted.
+    next_free_u: Option<u16>,
+}
+
+impl Row {
+    fn new(block_len: u16) -> Row {
+        Row {
+            block_len: block_len,
+            next_free_u: Some(0),
+        }
+    }
+}
+
+/// A single GPU-resident cache of variable-length `vec4` blocks, backed by
+/// one RGBA32F texture. Primitives `request` a block, write their payload,
+/// and keep the returned `GpuCacheHandle` across frames; the cache only
+/// re-uploads blocks whose content changed, and reuses the texels of
+/// blocks that were not requested this frame.
+pub struct GpuCache {
+    texture_id: CacheTextureId,
+    // The block pool. Allocated and free blocks both live here; free blocks
+    // are reachable from `free_lists`.
+    blocks: Vec<Block>,
+    // One row per allocated texture row. The index of a row is its `v`.
+    rows: Vec<Row>,
+    // Free lists keyed by block length; each entry is the head of a
+    // singly-linked list threaded through `Block::next_free`.
+    free_lists: HashMap<u16, Option<BlockIndex>>,
+    // CPU-side mirror of the texture, one entry per texel.
+    cpu_blocks: Vec<GpuBlockData>,
+    // Blocks whose payload changed this frame and must be re-uploaded.
+    dirty_blocks: Vec<BlockIndex>,
+    frame_id: FrameId,
+}
+
+impl GpuCache {
+    pub fn new(texture_id: CacheTextureId) -> GpuCache {
+        GpuCache {
+            texture_id: texture_id,
+            blocks: Vec::new(),
+            rows: Vec::new(),
+            free_lists: HashMap::new(),
+            cpu_blocks: Vec::new(),
+            dirty_blocks: Vec::new(),
+            frame_id: FrameId(0),
+        }
+    }
+
+    pub fn begin_frame(&mut self) {
+        // Reclaim every block that went a whole frame without being
+        // requested, returning its texels to the free lists for reuse.
+        self.evict_stale_blocks();
+        self.frame_id.0 += 1;
+        self.dirty_blocks.clear();
+    }
+
+    // Free any allocated block whose content was not requested during the
+    // frame that just finished.
+    fn evict_stale_blocks(&mut self) {
+        let current = self.frame_id;
+        let stale: Vec<BlockIndex> = self.blocks
+                                         .iter()
+                                         .enumerate()
+                                         .filter(|&(_, block)| block.allocated && block.epoch < current)
+                                         .map(|(i, _)| BlockIndex(i))
+                                         .collect();
+        for block_index in stale {
+            self.free_block(block_index);
+        }
+    }
+
+    // Ensure `handle` points at a live block of `len` texels. The returned
+    // `GpuCacheRequest` carries the block address and a `needs_write` flag:
+    // when it is set the block was (re)allocated and every texel must be
+    // filled via `set_block`; when it is clear the previous contents are
+    // still valid and an unchanged caller may skip writing.
+    pub fn request(&mut self, handle: &mut GpuCacheHandle, len: usize) -> GpuCacheRequest {
+        assert!(len <= GPU_CACHE_TEXTURE_WIDTH as usize);
+        let len = len as u16;
+
+        // Re-use the existing allocation when we still own a block of the
+        // right size. Touching its epoch keeps it from being swept this
+        // frame and preserves the address so unchanged content is not
+        // re-uploaded.
+        if let Some(location) = handle.location {
+            let block = self.blocks[location.block_index.0];
+            if block.allocated && block.len == len && block.generation == location.generation {
+                self.blocks[location.block_index.0].epoch = self.frame_id;
+                return GpuCacheRequest {
+                    address: block.address,
+                    needs_write: false,
+                };
+            }
+            // Our previous block was evicted, reused by another handle, or
+            // is the wrong size. Release it only if we still own it.
+            if block.allocated && block.generation == location.generation {
+                self.free_block(location.block_index);
+            }
+        }
+
+        let block_index = self.allocate_block(len);
+        self.blocks[block_index.0].epoch = self.frame_id;
+        handle.location = Some(CacheLocation {
+            block_index: block_index,
+            generation: self.blocks[block_index.0].generation,
+        });
+        GpuCacheRequest {
+            address: self.blocks[block_index.0].address,
+            needs_write: true,
+        }
+    }
+
+    // Write a single texel of a previously requested block.
+    pub fn set_block(&mut self, handle: &GpuCacheHandle, offset: usize, data: GpuBlockData) {
+        let location = handle.location.expect("set_block on an unrequested handle");
+        let block = self.blocks[location.block_index.0];
+        debug_assert!((offset as u16) < block.len);
+        let index = self.texel_index(block.address) + offset;
+        self.cpu_blocks[index] = data;
+        // Record the block dirty at most once per frame, regardless of how
+        // many of its texels are written, so `updates` does not emit a
+        // duplicate region per texel.
+        if self.blocks[location.block_index.0].last_dirty_frame != self.frame_id {
+            self.blocks[location.block_index.0].last_dirty_frame = self.frame_id;
+            self.dirty_blocks.push(location.block_index);
+        }
+    }
+
+    fn texel_index(&self, address: GpuCacheAddress) -> usize {
+        address.v as usize * GPU_CACHE_TEXTURE_WIDTH as usize + address.u as usize
+    }
+
+    fn allocate_block(&mut self, len: u16) -> BlockIndex {
+        // Fast path: a freed block of exactly this length is waiting to be
+        // reused, keeping allocation O(1).
+        if let Some(head) = self.free_lists.get(&len).cloned().and_then(|h| h) {
+            let next = self.blocks[head.0].next_free;
+            self.free_lists.insert(len, next);
+            self.blocks[head.0].next_free = None;
+            self.blocks[head.0].allocated = true;
+            self.blocks[head.0].epoch = self.frame_id;
+            // Re-handing out a freed block invalidates any handle that still
+            // references it.
+            self.blocks[head.0].generation = self.blocks[head.0].generation.wrapping_add(1);
+            return head;
+        }
+
+        // Otherwise bump-allocate from a row that carves blocks of this
+        // length, growing the texture by a row when none has space left.
+        let address = self.bump_allocate(len);
+        let block_index = BlockIndex(self.blocks.len());
+        self.blocks.push(Block {
+            address: address,
+            len: len,
+            next_free: None,
+            epoch: self.frame_id,
+            generation: 0,
+            allocated: true,
+            last_dirty_frame: FrameId(usize::MAX),
+        });
+        block_index
+    }
+
+    fn bump_allocate(&mut self, len: u16) -> GpuCacheAddress {
+        for (v, row) in self.rows.iter_mut().enumerate() {
+            if row.block_len != len {
+                continue;
+            }
+            if let Some(u) = row.next_free_u {
+                let next = u + len;
+                row.next_free_u = if next + len <= GPU_CACHE_TEXTURE_WIDTH as u16 {
+                    Some(next)
+                } else {
+                    None
+                };
+                return GpuCacheAddress { u: u, v: v as u16 };
+            }
+        }
+
+        // Grow by a fresh row dedicated to this block length.
+        let v = self.rows.len() as u16;
+        let mut row = Row::new(len);
+        row.next_free_u = if len * 2 <= GPU_CACHE_TEXTURE_WIDTH as u16 {
+            Some(len)
+        } else {
+            None
+        };
+        self.rows.push(row);
+        self.cpu_blocks.resize(self.cpu_blocks.len() + GPU_CACHE_TEXTURE_WIDTH as usize,
+                               GpuBlockData::empty());
+        GpuCacheAddress { u: 0, v: v }
+    }
+
+    fn free_block(&mut self, block_index: BlockIndex) {
+        let len = self.blocks[block_index.0].len;
+        let head = self.free_lists.get(&len).cloned().and_then(|h| h);
+        self.blocks[block_index.0].next_free = head;
+        self.blocks[block_index.0].allocated = false;
+        self.free_lists.insert(len, Some(block_index));
+    }
+
+    pub fn texture_id(&self) -> CacheTextureId {
+        self.texture_id
+    }
+
+    /// Flush all the blocks that changed this frame into a texture update
+    /// list, coalescing runs of texels that are contiguous within a row so
+    /// each row produces as few `TextureUpdateOp::Update` regions as
+    /// possible.
+    pub fn updates(&mut self) -> TextureUpdateList {
+        let mut list = TextureUpdateList::new();
+
+        // Sort the dirty blocks into row order so contiguous runs end up
+        // adjacent and can be merged.
+        let mut dirty: Vec<(GpuCacheAddress, u16)> = self.dirty_blocks.iter().map(|block_index| {
+            let block = self.blocks[block_index.0];
+            (block.address, block.len)
+        }).collect();
+        dirty.sort_by(|a, b| (a.0.v, a.0.u).cmp(&(b.0.v, b.0.u)));
+
+        let mut i = 0;
+        while i < dirty.len() {
+            let (start, mut len) = dirty[i];
+            let v = start.v;
+            let mut end_u = start.u + len;
+            let mut j = i + 1;
+            while j < dirty.len() && dirty[j].0.v == v && dirty[j].0.u == end_u {
+                end_u += dirty[j].1;
+                len += dirty[j].1;
+                j += 1;
+            }
+
+            let offset = self.texel_index(start);
+            let mut pixels = Vec::with_capacity(len as usize * 16);
+            for texel in &self.cpu_blocks[offset..offset + len as usize] {
+                for component in &texel.data {
+                    pixels.extend_from_slice(&f32_to_bytes(*component));
+                }
+            }
+
+            list.push(TextureUpdate {
+                id: self.texture_id,
+                op: TextureUpdateOp::Update(start.u as u32,
+                                            v as u32,
+                                            len as u32,
+                                            1,
+                                            Arc::new(pixels),
+                                            None),
+            });
+            i = j;
+        }
+
+        list
+    }
+}
+
+fn f32_to_bytes(value: f32) -> [u8; 4] {
+    let bits = value.to_bits();
+    [bits as u8,
+     (bits >> 8) as u8,
+     (bits >> 16) as u8,
+     (bits >> 24) as u8]
+}
+
 /// Mostly wraps a tiling::Frame, adding a bit of extra information.
 pub struct RendererFrame {
+    /// The document this frame belongs to. The rendering thread keeps one
+    /// `RendererFrame` per live document and recomposites them all each
+    /// vsync, re-tiling only the ones whose backend produced a new frame.
+    pub document_id: DocumentId,
     /// The last rendered epoch for each pipeline present in the frame.
     /// This information is used to know if a certain transformation on the layout has
     /// been rendered, which is necessary for reftests.
     pub pipeline_epoch_map: HashMap<PipelineId, Epoch, BuildHasherDefault<FnvHasher>>,
     /// The layers that are currently affected by the over-scrolling animation.
     pub layers_bouncing_back: HashSet<ScrollLayerId, BuildHasherDefault<FnvHasher>>,
+    /// Pipelines whose sub-tree should be rendered into a dedicated output
+    /// target and handed back to the embedder as a texture, instead of (or
+    /// in addition to) being composited into the main framebuffer.
+    pub output_pipelines: HashSet<PipelineId, BuildHasherDefault<FnvHasher>>,
 
     pub frame: Option<tiling::Frame>,
 }
 
 impl RendererFrame {
-    pub fn new(pipeline_epoch_map: HashMap<PipelineId, Epoch, BuildHasherDefault<FnvHasher>>,
+    pub fn new(document_id: DocumentId,
+               pipeline_epoch_map: HashMap<PipelineId, Epoch, BuildHasherDefault<FnvHasher>>,
                layers_bouncing_back: HashSet<ScrollLayerId, BuildHasherDefault<FnvHasher>>,
+               output_pipelines: HashSet<PipelineId, BuildHasherDefault<FnvHasher>>,
                frame: Option<tiling::Frame>)
                -> RendererFrame {
         RendererFrame {
+            document_id: document_id,
             pipeline_epoch_map: pipeline_epoch_map,
             layers_bouncing_back: layers_bouncing_back,
+            output_pipelines: output_pipelines,
             frame: frame,
         }
     }
 }
 
+/// The render target a flagged output pipeline was drawn into, reported
+/// back to the embedder each frame. The native texture id can be bound by
+/// an outer GL compositor, or the texture fed back in as a
+/// `SourceTexture::TextureCache`.
+pub struct PipelineOutput {
+    pub texture_id: CacheTextureId,
+    pub native_texture_id: u32,     // Is actually a gl::GLuint
+    pub size: Size2D<i32>,
+    pub uv: RectUv<f32>,
+}
+
 pub enum ResultMsg {
-    UpdateTextureCache(TextureUpdateList),
+    UpdateTextureCache(DocumentId, TextureUpdateList),
     RefreshShader(PathBuf),
-    NewFrame(RendererFrame, BackendProfileCounters),
+    NewFrame(DocumentId, RendererFrame, BackendProfileCounters),
+    PipelineOutput(DocumentId, PipelineId, PipelineOutput),
 }
 
 #[repr(u32)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "capture", derive(Deserialize, Serialize))]
 pub enum AxisDirection {
     Horizontal,
     Vertical,
@@ -415,6 +880,7 @@ pub enum AxisDirection {
 pub struct StackingContextIndex(pub usize);
 
 #[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "capture", derive(Deserialize, Serialize))]
 pub struct RectUv<T, U = UnknownUnit> {
     pub top_left: TypedPoint2D<T, U>,
     pub top_right: TypedPoint2D<T, U>,
@@ -423,9 +889,17 @@ pub struct RectUv<T, U = UnknownUnit> {
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "capture", derive(Deserialize, Serialize))]
 pub enum LowLevelFilterOp {
     Blur(Au, AxisDirection),
     Brightness(Au),
+    /// An arbitrary color transform `rgb' = M * rgba + offset`, where the
+    /// first four `Au` groups are the rows of the 4x4 linear part `M` and
+    /// the last group is the per-channel offset vector. Alpha is handled by
+    /// the fourth row. Coefficients are stored fixed-point so the enum can
+    /// keep its `Eq`/`Hash` derives. This subsumes `Grayscale`, `Sepia`,
+    /// and `Saturate` as special cases and expresses SVG `feColorMatrix`.
+    ColorMatrix([Au; 20]),
     Contrast(Au),
     Grayscale(Au),
     /// Fixed-point in `ANGLE_FLOAT_TO_FIXED` units.
@@ -437,7 +911,164 @@ pub enum LowLevelFilterOp {
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "capture", derive(Deserialize, Serialize))]
 pub enum CompositionOp {
-    MixBlend(MixBlendMode),
+    MixBlend(#[cfg_attr(feature = "capture", serde(with = "capture::MixBlendModeDef"))]
+             MixBlendMode),
     Filter(LowLevelFilterOp),
-}
\ No newline at end of file
+}
+
+// Serialization support for the RON-based capture/replay path. Pixel
+// payloads are kept out of the RON and written to sidecar `.bin` files
+// referenced by name, mirroring the way `FontTemplate::Raw`/`Native`
+// distinguish inline bytes from an external handle, so that a captured
+// frame stays small enough to read by hand.
+#[cfg(feature = "capture")]
+pub mod capture {
+    use std::cell::RefCell;
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use super::{ImageFormat, MixBlendMode, TextureFilter};
+
+    // Remote derives for the foreign enums reachable through the captured
+    // types, since the derive can't be attached to a type defined in
+    // another crate. Each mirror is used via `serde(with = "…Def")` on the
+    // field that holds the foreign value.
+
+    #[derive(Deserialize, Serialize)]
+    #[serde(remote = "ImageFormat")]
+    pub enum ImageFormatDef {
+        Invalid,
+        A8,
+        RGB8,
+        RGBA8,
+        RGBAF32,
+    }
+
+    #[derive(Deserialize, Serialize)]
+    #[serde(remote = "TextureFilter")]
+    pub enum TextureFilterDef {
+        Nearest,
+        Linear,
+    }
+
+    #[derive(Deserialize, Serialize)]
+    #[serde(remote = "MixBlendMode")]
+    pub enum MixBlendModeDef {
+        Normal,
+        Multiply,
+        Screen,
+        Overlay,
+        Darken,
+        Lighten,
+        ColorDodge,
+        ColorBurn,
+        HardLight,
+        SoftLight,
+        Difference,
+        Exclusion,
+        Hue,
+        Saturation,
+        Color,
+        Luminosity,
+    }
+
+    thread_local! {
+        // The directory the active capture is being written to / read
+        // from, and a running counter used to name the sidecar blobs.
+        static ROOT: RefCell<Option<PathBuf>> = RefCell::new(None);
+        static NEXT_ID: RefCell<u32> = RefCell::new(0);
+    }
+
+    /// Bind the capture directory for the duration of a serialize/deserialize
+    /// pass. The loader and the saver wrap their work in this so the blob
+    /// helpers know where to place or find the sidecar files.
+    pub fn with_root<F, R>(root: PathBuf, f: F) -> R where F: FnOnce() -> R {
+        ROOT.with(|r| *r.borrow_mut() = Some(root));
+        NEXT_ID.with(|n| *n.borrow_mut() = 0);
+        let result = f();
+        ROOT.with(|r| *r.borrow_mut() = None);
+        result
+    }
+
+    fn root() -> PathBuf {
+        ROOT.with(|r| r.borrow().clone()).expect("capture root not bound")
+    }
+
+    fn next_name() -> String {
+        NEXT_ID.with(|n| {
+            let id = *n.borrow();
+            *n.borrow_mut() = id + 1;
+            format!("blob_{}.bin", id)
+        })
+    }
+
+    pub mod blob {
+        use super::*;
+
+        pub fn serialize<S>(bytes: &Arc<Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error>
+            where S: Serializer
+        {
+            let name = next_name();
+            let mut file = File::create(root().join(&name)).map_err(serde_error::<S>)?;
+            file.write_all(bytes).map_err(serde_error::<S>)?;
+            name.serialize(serializer)
+        }
+
+        pub fn deserialize<'de, D>(deserializer: D) -> Result<Arc<Vec<u8>>, D::Error>
+            where D: Deserializer<'de>
+        {
+            let name = String::deserialize(deserializer)?;
+            let mut bytes = Vec::new();
+            let mut file = File::open(root().join(&name)).map_err(de_error::<D>)?;
+            file.read_to_end(&mut bytes).map_err(de_error::<D>)?;
+            Ok(Arc::new(bytes))
+        }
+    }
+
+    pub mod opt_blob {
+        use super::*;
+
+        pub fn serialize<S>(bytes: &Option<Arc<Vec<u8>>>, serializer: S) -> Result<S::Ok, S::Error>
+            where S: Serializer
+        {
+            match *bytes {
+                Some(ref bytes) => {
+                    let name = next_name();
+                    let mut file = File::create(root().join(&name)).map_err(serde_error::<S>)?;
+                    file.write_all(bytes).map_err(serde_error::<S>)?;
+                    Some(name).serialize(serializer)
+                }
+                None => None::<String>.serialize(serializer),
+            }
+        }
+
+        pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<Arc<Vec<u8>>>, D::Error>
+            where D: Deserializer<'de>
+        {
+            let name = Option::<String>::deserialize(deserializer)?;
+            match name {
+                Some(name) => {
+                    let mut bytes = Vec::new();
+                    let mut file = File::open(root().join(&name)).map_err(de_error::<D>)?;
+                    file.read_to_end(&mut bytes).map_err(de_error::<D>)?;
+                    Ok(Some(Arc::new(bytes)))
+                }
+                None => Ok(None),
+            }
+        }
+    }
+
+    fn serde_error<S: Serializer>(err: ::std::io::Error) -> S::Error {
+        use serde::ser::Error;
+        S::Error::custom(format!("capture blob: {}", err))
+    }
+
+    fn de_error<'de, D: Deserializer<'de>>(err: ::std::io::Error) -> D::Error {
+        use serde::de::Error;
+        D::Error::custom(format!("capture blob: {}", err))
+    }
+}